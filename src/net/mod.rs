@@ -1,25 +1,50 @@
-use super::rt::{ExecutorRef, Config};
+use core::marker::PhantomData;
+use std::os::unix::io::AsRawFd;
+use super::rt::{Registry, Config};
 
-pin_project_lite::pin_project! {
-    pub struct WithRegistry<T, C>
-    where
-        C: Config,
-    {
-        inner: T,
-        executor: ExecutorRef<C>,
-        registered: bool,
-    }
+// No field here is ever pinned in place (an fd-backed handle like
+// `TcpStream` doesn't need structural pinning), so `WithRegistry` is a plain
+// struct rather than a `pin_project`-generated one; that leaves it free to
+// implement `Drop` directly to deregister its fd (see `net_unix` below).
+//
+// `T: AsRawFd` is carried on the struct itself (not just the `net_unix`
+// impls) so the unconditional `Drop` impl below -- which needs to recover
+// the fd to deregister it -- doesn't try to impose a bound `Drop` impls
+// aren't allowed to add on their own.
+pub struct WithRegistry<T, C>
+where
+    T: AsRawFd,
+    C: Config,
+{
+    inner: T,
+    executor: Registry,
+    registered: Option<(mio::Token, mio::Interest)>,
+    _phantom: PhantomData<C>,
+}
+
+// No field is ever pinned in place, so this holds for any `T: Unpin`
+// regardless of `C`. Spelled out explicitly because the auto-derived impl
+// would otherwise also demand `C: Unpin`: `PhantomData<C>` carries the auto
+// traits of `C`, so it is only `Unpin` when `C` is, even though no `C` is
+// ever actually held by value.
+impl<T, C> Unpin for WithRegistry<T, C>
+where
+    T: AsRawFd + Unpin,
+    C: Config,
+{
 }
 
 impl<T, C> WithRegistry<T, C>
 where
+    T: AsRawFd,
     C: Config,
 {
-    pub fn new(inner: T, executor: &ExecutorRef<C>) -> Self {
+    pub fn new(inner: T, executor: &Registry) -> Self {
         WithRegistry {
             inner,
             executor: executor.clone(),
-            registered: false,
+            registered: None,
+            _phantom: PhantomData,
         }
     }
 }
@@ -31,12 +56,15 @@ mod net_unix {
         task::{Poll, Context},
     };
     use std::{
-        io::{self, Read},
-        net::{TcpListener, TcpStream, SocketAddr},
-        os::unix::io::AsRawFd,
+        io::{self, Read, Write},
+        net::{TcpListener, TcpStream, SocketAddr, UdpSocket},
+        os::unix::{
+            io::AsRawFd,
+            net::{UnixListener, UnixStream, UnixDatagram, SocketAddr as UnixSocketAddr},
+        },
     };
-    use futures::{Stream, AsyncRead};
-    use mio::{unix::SourceFd, Token, Interest};
+    use futures::{future, Stream, AsyncRead, AsyncWrite};
+    use mio::{unix::SourceFd, Interest};
     use super::{WithRegistry, super::rt::{Config, Reg}};
 
     impl<T, C> WithRegistry<T, C>
@@ -44,13 +72,55 @@ mod net_unix {
         T: AsRawFd,
         C: Config,
     {
+        /// Register the fd for `interest` if it isn't yet, or widen an
+        /// existing registration (e.g. read-only -> read+write) so that a
+        /// single fd used for both directions only ever has one token.
+        fn ensure_registered(&mut self, interest: Interest) -> Result<(), io::Error> {
+            let fd = self.inner.as_raw_fd();
+            let mut source = SourceFd(&fd);
+            match self.registered {
+                None => {
+                    let token = self.executor.register(&mut source, Reg::Reg(interest))?;
+                    self.registered = Some((token, interest));
+                }
+                Some((token, current)) if (current | interest) != current => {
+                    let combined = current | interest;
+                    self.executor
+                        .register(&mut source, Reg::ReReg(token, combined))?;
+                    self.registered = Some((token, combined));
+                }
+                Some(_) => {}
+            }
+            Ok(())
+        }
+
         pub fn deregister(&mut self) -> Result<(), io::Error> {
-            if self.registered {
+            if let Some((token, _)) = self.registered {
                 let fd = self.inner.as_raw_fd();
                 let mut source = SourceFd(&fd);
-                self.executor.register(&mut source, Reg::DeReg)
-            } else {
-                Ok(())
+                self.executor.register(&mut source, Reg::DeReg(token))?;
+                self.registered = None;
+            }
+            Ok(())
+        }
+    }
+
+    impl<T, C> Drop for WithRegistry<T, C>
+    where
+        T: AsRawFd,
+        C: Config,
+    {
+        fn drop(&mut self) {
+            // the fd is about to close (the inner handle is dropped right
+            // after this), so pull its token out of the reactor first --
+            // otherwise a reused fd could be mistaken for this one. Use the
+            // best-effort path: if the executor already shut down there is
+            // nothing left to clean up.
+            if let Some((token, _)) = self.registered {
+                let fd = self.inner.as_raw_fd();
+                let mut source = SourceFd(&fd);
+                let _ = self.executor.try_register(&mut source, Reg::DeReg(token));
+                self.registered = None;
             }
         }
     }
@@ -64,13 +134,29 @@ mod net_unix {
         fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
             let _ = cx;
 
-            let fd = self.inner.as_raw_fd();
-            let mut source = SourceFd(&fd);
-            if !self.registered {
-                self.executor
-                    .register(&mut source, Reg::Reg(Token(fd as _), Interest::READABLE))?;
-                self.registered = true;
+            self.ensure_registered(Interest::READABLE)?;
+
+            match self.inner.accept() {
+                Ok(p) => Poll::Ready(Some(Ok(p))),
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => Poll::Pending,
+                    io::ErrorKind::UnexpectedEof => Poll::Ready(None),
+                    _ => Poll::Ready(Some(Err(e))),
+                },
             }
+        }
+    }
+
+    impl<C> Stream for WithRegistry<UnixListener, C>
+    where
+        C: Config,
+    {
+        type Item = Result<(UnixStream, UnixSocketAddr), io::Error>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let _ = cx;
+
+            self.ensure_registered(Interest::READABLE)?;
 
             match self.inner.accept() {
                 Ok(p) => Poll::Ready(Some(Ok(p))),
@@ -83,28 +169,128 @@ mod net_unix {
         }
     }
 
+    impl<C> WithRegistry<UdpSocket, C>
+    where
+        C: Config,
+    {
+        pub async fn send_to(&mut self, buf: &[u8], target: SocketAddr) -> Result<usize, io::Error> {
+            future::poll_fn(|cx| self.poll_send_to(cx, buf, target)).await
+        }
+
+        pub async fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+            future::poll_fn(|cx| self.poll_recv_from(cx, buf)).await
+        }
+
+        fn poll_send_to(
+            &mut self,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+            target: SocketAddr,
+        ) -> Poll<Result<usize, io::Error>> {
+            let _ = cx;
+
+            self.ensure_registered(Interest::WRITABLE)?;
+
+            match self.inner.send_to(buf, target) {
+                Ok(written) => Poll::Ready(Ok(written)),
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => Poll::Pending,
+                    _ => Poll::Ready(Err(e)),
+                },
+            }
+        }
+
+        fn poll_recv_from(
+            &mut self,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<Result<(usize, SocketAddr), io::Error>> {
+            let _ = cx;
+
+            self.ensure_registered(Interest::READABLE)?;
+
+            match self.inner.recv_from(buf) {
+                Ok(p) => Poll::Ready(Ok(p)),
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => Poll::Pending,
+                    _ => Poll::Ready(Err(e)),
+                },
+            }
+        }
+    }
+
+    impl<C> WithRegistry<UnixDatagram, C>
+    where
+        C: Config,
+    {
+        pub async fn send_to(
+            &mut self,
+            buf: &[u8],
+            target: impl AsRef<std::path::Path>,
+        ) -> Result<usize, io::Error> {
+            future::poll_fn(|cx| self.poll_send_to(cx, buf, target.as_ref())).await
+        }
+
+        pub async fn recv_from(
+            &mut self,
+            buf: &mut [u8],
+        ) -> Result<(usize, UnixSocketAddr), io::Error> {
+            future::poll_fn(|cx| self.poll_recv_from(cx, buf)).await
+        }
+
+        fn poll_send_to(
+            &mut self,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+            target: &std::path::Path,
+        ) -> Poll<Result<usize, io::Error>> {
+            let _ = cx;
+
+            self.ensure_registered(Interest::WRITABLE)?;
+
+            match self.inner.send_to(buf, target) {
+                Ok(written) => Poll::Ready(Ok(written)),
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => Poll::Pending,
+                    _ => Poll::Ready(Err(e)),
+                },
+            }
+        }
+
+        fn poll_recv_from(
+            &mut self,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<Result<(usize, UnixSocketAddr), io::Error>> {
+            let _ = cx;
+
+            self.ensure_registered(Interest::READABLE)?;
+
+            match self.inner.recv_from(buf) {
+                Ok(p) => Poll::Ready(Ok(p)),
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => Poll::Pending,
+                    _ => Poll::Ready(Err(e)),
+                },
+            }
+        }
+    }
+
     impl<T, C> AsyncRead for WithRegistry<T, C>
     where
-        T: AsRawFd + Read,
+        T: AsRawFd + Read + Unpin,
         C: Config,
     {
         fn poll_read(
-            self: Pin<&mut Self>,
+            mut self: Pin<&mut Self>,
             cx: &mut Context<'_>,
             buf: &mut [u8],
         ) -> Poll<Result<usize, io::Error>> {
             let _ = cx;
-            let this = self.project();
 
-            let fd = this.inner.as_raw_fd();
-            let mut source = SourceFd(&fd);
-            if !*this.registered {
-                this.executor
-                    .register(&mut source, Reg::Reg(Token(fd as _), Interest::READABLE))?;
-                *this.registered = true;
-            }
+            self.ensure_registered(Interest::READABLE)?;
 
-            match this.inner.read(buf) {
+            match self.inner.read(buf) {
                 Ok(read) => Poll::Ready(Ok(read)),
                 Err(e) => match e.kind() {
                     io::ErrorKind::WouldBlock => Poll::Pending,
@@ -113,4 +299,55 @@ mod net_unix {
             }
         }
     }
+
+    impl<T, C> AsyncWrite for WithRegistry<T, C>
+    where
+        T: AsRawFd + Write + Unpin,
+        C: Config,
+    {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize, io::Error>> {
+            let _ = cx;
+
+            self.ensure_registered(Interest::WRITABLE)?;
+
+            match self.inner.write(buf) {
+                Ok(written) => Poll::Ready(Ok(written)),
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => Poll::Pending,
+                    _ => Poll::Ready(Err(e)),
+                },
+            }
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), io::Error>> {
+            let _ = cx;
+
+            match self.inner.flush() {
+                Ok(()) => Poll::Ready(Ok(())),
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => Poll::Pending,
+                    _ => Poll::Ready(Err(e)),
+                },
+            }
+        }
+
+        fn poll_close(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), io::Error>> {
+            let _ = cx;
+
+            // best-effort flush of the write half before we stop tracking it
+            let _ = self.inner.flush();
+            self.deregister()?;
+            Poll::Ready(Ok(()))
+        }
+    }
 }
@@ -1,38 +1,49 @@
 use core::{
     future::Future,
-    task::{Context, Waker, RawWaker, RawWakerVTable},
-    ptr,
+    pin::Pin,
+    task::{Context, Poll as TaskPoll, Waker, RawWaker, RawWakerVTable},
+    mem,
     marker::PhantomData,
     hash::Hash,
     fmt,
 };
 use std::{
     sync::{Arc, Weak},
-    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+    collections::{HashMap, HashSet, BTreeMap},
+    time::{Duration, Instant},
     io,
-    sync::Mutex,
+    sync::{Mutex, Condvar},
+    cell::RefCell,
+    thread,
 };
 use futures::future::BoxFuture;
-use crossbeam::deque::{Injector, Steal};
+use crossbeam::deque::{Injector, Steal, Worker, Stealer};
 use mio::{Poll, Token, event::Source, Events, Interest};
 
 impl<T> Config for T
 where
-    T: Eq + Hash + fmt::Display,
+    T: Eq + Hash + Clone + fmt::Display,
 {
     type TaskId = T;
 }
 
 pub trait Config {
-    type TaskId: Eq + Hash + fmt::Display;
+    type TaskId: Eq + Hash + Clone + fmt::Display;
 }
 
+/// Reserved token used to wake the `mio::Poll` from another thread (or from
+/// inside the loop itself) whenever a task becomes ready without a matching
+/// readiness event.
+const WAKE_TOKEN: Token = Token(usize::MAX);
+
 pub struct Executor<C>
 where
     C: Config,
 {
     spawner: Arc<SpawnerInner<C>>,
     registry: Arc<Mutex<RegistryInner>>,
+    waker: Arc<mio::Waker>,
 }
 
 #[derive(Clone)]
@@ -42,19 +53,141 @@ pub struct Registry {
 
 struct RegistryInner {
     poll: Poll,
-    last_token: Option<Token>,
+    next_token: usize,
+    // deadline, tie-breaker id (insertion order) -> waker to fire at that deadline
+    timers: BTreeMap<(Instant, u64), Waker>,
+    next_timer_id: u64,
+}
+
+thread_local! {
+    // register/deregister calls made by `apply_reg` since the last
+    // `take_recent_events` call on this thread, in order. This used to be a
+    // field on the shared `RegistryInner`, but a task's `future.poll()` runs
+    // with the registry lock released, so two workers polling different
+    // tasks at once would interleave their registrations into the same
+    // buffer and steal each other's events. Every registration a task makes
+    // happens synchronously on the thread that is polling it, so recording
+    // per-thread instead of per-registry keeps each poll's events isolated
+    // without needing a lock.
+    static RECENT_EVENTS: RefCell<Vec<RegEvent>> = const { RefCell::new(Vec::new()) };
+}
+
+fn take_recent_events() -> Vec<RegEvent> {
+    RECENT_EVENTS.with(|events| mem::take(&mut *events.borrow_mut()))
+}
+
+/// A single register/deregister call observed by the reactor, recorded so
+/// the executor can learn the full set of tokens a task is interested in.
+#[derive(Debug, Clone, Copy)]
+enum RegEvent {
+    Reg(Token),
+    DeReg(Token),
 }
 
 impl RegistryInner {
     fn new() -> Result<Self, io::Error> {
         Ok(RegistryInner {
             poll: Poll::new()?,
-            last_token: None,
+            next_token: 0,
+            timers: BTreeMap::new(),
+            next_timer_id: 0,
         })
     }
 
-    fn last(&self) -> Option<Token> {
-        self.last_token.clone()
+    fn alloc_token(&mut self) -> Token {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        token
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.timers.keys().next().map(|&(deadline, _)| deadline)
+    }
+
+    fn fire_expired_timers(&mut self) {
+        let now = Instant::now();
+        let pending = self.timers.split_off(&(now, 0));
+        let expired = mem::replace(&mut self.timers, pending);
+        for (_, waker) in expired {
+            waker.wake();
+        }
+    }
+}
+
+/// What to do with a source passed to [`Registry::register`]. A fresh
+/// registration doesn't carry a `Token`: the registry allocates one (rather
+/// than the caller reusing e.g. the raw fd value, which the OS can recycle)
+/// and hands it back so the caller can use it for later `ReReg`/`DeReg`
+/// calls and can track it alongside its other registrations.
+#[derive(Debug, Clone, Copy)]
+pub enum Reg {
+    Reg(Interest),
+    ReReg(Token, Interest),
+    DeReg(Token),
+}
+
+fn apply_reg<S>(s: &mut RegistryInner, source: &mut S, reg: Reg) -> Result<Token, io::Error>
+where
+    S: Source + ?Sized + fmt::Debug,
+{
+    match reg {
+        Reg::Reg(interests) => {
+            let token = s.alloc_token();
+            s.poll.registry().register(source, token, interests)?;
+            RECENT_EVENTS.with(|events| events.borrow_mut().push(RegEvent::Reg(token)));
+            Ok(token)
+        }
+        Reg::ReReg(token, interests) => {
+            s.poll.registry().reregister(source, token, interests)?;
+            Ok(token)
+        }
+        Reg::DeReg(token) => {
+            s.poll.registry().deregister(source)?;
+            RECENT_EVENTS.with(|events| events.borrow_mut().push(RegEvent::DeReg(token)));
+            Ok(token)
+        }
+    }
+}
+
+/// Poll a task once, returning whether it's still pending along with every
+/// register/deregister call it made during this specific poll (so the
+/// caller can keep its token bookkeeping in sync regardless of which thread
+/// or queue is driving the task). A task is only ever polled by the thread
+/// that calls this function, so the events are recorded thread-locally
+/// rather than on the (possibly shared) registry -- see `RECENT_EVENTS`.
+fn poll_and_collect<C>(waker: &Waker, task: &mut Task<C>) -> (bool, Vec<RegEvent>)
+where
+    C: Config,
+{
+    take_recent_events();
+    let mut cx = Context::from_waker(waker);
+    let pending = task.future.as_mut().poll(&mut cx).is_pending();
+    let events = take_recent_events();
+    (pending, events)
+}
+
+/// Fold a batch of `RegEvent`s into the shared token bookkeeping: which
+/// token(s) `id` currently owns, and which task owns each live token.
+fn apply_token_events<C>(
+    token_owner: &mut HashMap<Token, C::TaskId>,
+    task_tokens: &mut HashMap<C::TaskId, HashSet<Token>>,
+    id: &C::TaskId,
+    events: Vec<RegEvent>,
+) where
+    C: Config,
+{
+    let set = task_tokens.entry(id.clone()).or_default();
+    for event in events {
+        match event {
+            RegEvent::Reg(token) => {
+                set.insert(token);
+                token_owner.insert(token, id.clone());
+            }
+            RegEvent::DeReg(token) => {
+                set.remove(&token);
+                token_owner.remove(&token);
+            }
+        }
     }
 }
 
@@ -68,52 +201,100 @@ impl Registry {
         f(&mut s)
     }
 
-    pub fn register<S>(
-        &self,
-        source: &mut S,
-        token: usize,
-        interests: Interest,
-    ) -> Result<(), io::Error>
+    /// Like `inner`, but returns `None` instead of panicking if the executor
+    /// has already been torn down. Used on cleanup paths (e.g. `Drop`) where
+    /// a dead registry just means there is nothing left to clean up.
+    fn try_inner<F, T>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut RegistryInner) -> T,
+    {
+        let s = self.inner.upgrade()?;
+        let mut s = s.lock().unwrap();
+        Some(f(&mut s))
+    }
+
+    pub fn register<S>(&self, source: &mut S, reg: Reg) -> Result<Token, io::Error>
     where
         S: Source + ?Sized + fmt::Debug,
     {
-        log::debug!("register {:?} {:?} {:?}", source, token, interests);
+        log::debug!("register {:?} {:?}", source, reg);
 
-        self.inner(|s| {
-            s.last_token = Some(Token(token));
-            s.poll.registry().register(source, Token(token), interests)
-        })
+        self.inner(|s| apply_reg(s, source, reg))
     }
 
-    pub fn reregister<S>(
+    /// Best-effort version of `register` for use on drop paths: if the
+    /// executor is already gone, the fd is being closed anyway and there is
+    /// nothing to deregister.
+    pub(crate) fn try_register<S>(
         &self,
         source: &mut S,
-        token: usize,
-        interests: Interest,
-    ) -> Result<(), io::Error>
+        reg: Reg,
+    ) -> Option<Result<Token, io::Error>>
     where
         S: Source + ?Sized + fmt::Debug,
     {
-        log::debug!("reregister {:?} {:?} {:?}", source, token, interests);
+        log::debug!("try_register {:?} {:?}", source, reg);
 
-        self.inner(|s| {
-            s.last_token = Some(Token(token));
-            s.poll
-                .registry()
-                .reregister(source, Token(token), interests)
-        })
+        self.try_inner(|s| apply_reg(s, source, reg))
     }
 
-    pub fn deregister<S>(&self, source: &mut S) -> Result<(), io::Error>
-    where
-        S: Source + ?Sized + fmt::Debug,
-    {
-        log::debug!("deregister {:?}", source);
+    /// A future that resolves once `duration` has elapsed.
+    pub fn sleep(&self, duration: Duration) -> Timer {
+        Timer {
+            inner: self.inner.clone(),
+            deadline: Instant::now() + duration,
+            id: None,
+        }
+    }
+}
 
-        self.inner(|s| {
-            s.last_token = None;
-            s.poll.registry().deregister(source)
-        })
+/// Future returned by [`Registry::sleep`]. Registers its waker against a
+/// deadline in the executor's timer map and is woken once the executor's
+/// loop (`run_inner`, or a worker driving the reactor) observes that the
+/// deadline has passed.
+pub struct Timer {
+    inner: Weak<Mutex<RegistryInner>>,
+    deadline: Instant,
+    id: Option<u64>,
+}
+
+impl Timer {
+    fn key(&self) -> Option<(Instant, u64)> {
+        self.id.map(|id| (self.deadline, id))
+    }
+}
+
+impl Future for Timer {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> TaskPoll<()> {
+        if Instant::now() >= self.deadline {
+            if let (Some(key), Some(inner)) = (self.key(), self.inner.upgrade()) {
+                inner.lock().unwrap().timers.remove(&key);
+            }
+            return TaskPoll::Ready(());
+        }
+
+        let inner = match self.inner.upgrade() {
+            Some(inner) => inner,
+            None => return TaskPoll::Ready(()),
+        };
+        let mut guard = inner.lock().unwrap();
+        let id = *self.id.get_or_insert_with(|| {
+            let id = guard.next_timer_id;
+            guard.next_timer_id += 1;
+            id
+        });
+        guard.timers.insert((self.deadline, id), cx.waker().clone());
+        TaskPoll::Pending
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        if let (Some(key), Some(inner)) = (self.key(), self.inner.upgrade()) {
+            inner.lock().unwrap().timers.remove(&key);
+        }
     }
 }
 
@@ -156,6 +337,21 @@ where
     C: Config,
 {
     injector: Injector<Task<C>>,
+    // task ids that were woken and need to be re-polled regardless of any
+    // mio readiness event (e.g. woken from another thread, or woken
+    // spuriously while still inside `poll`).
+    ready: Injector<C::TaskId>,
+    // number of tasks spawned but not yet finished; only consulted by the
+    // multi-worker pool (`run_pool`) to detect global termination, but kept
+    // here (rather than threaded through separately) since `Spawner::spawn`
+    // is the single place a task comes into existence.
+    live: AtomicUsize,
+    // woken whenever `injector` or `ready` gains an entry, or `live` reaches
+    // zero, so a worker pool's idle workers (those that lost the race to
+    // drive the reactor) can block instead of busy-spinning while there is
+    // genuinely nothing for them to do.
+    idle_lock: Mutex<()>,
+    idle_cv: Condvar,
     phantom_data: PhantomData<C>,
 }
 
@@ -166,9 +362,17 @@ where
     fn new() -> Self {
         SpawnerInner {
             injector: Injector::new(),
+            ready: Injector::new(),
+            live: AtomicUsize::new(0),
+            idle_lock: Mutex::new(()),
+            idle_cv: Condvar::new(),
             phantom_data: PhantomData,
         }
     }
+
+    fn notify_idle(&self) {
+        self.idle_cv.notify_all();
+    }
 }
 
 impl<C> Clone for Spawner<C>
@@ -202,7 +406,86 @@ where
             id,
             future: Box::pin(future),
         };
-        self.inner.upgrade().unwrap().injector.push(task);
+        let inner = self.inner.upgrade().unwrap();
+        inner.live.fetch_add(1, Ordering::SeqCst);
+        inner.injector.push(task);
+        inner.notify_idle();
+    }
+}
+
+/// The data behind a task's `Waker`. Holds just enough to push the task id
+/// back onto the ready queue and to kick the `mio::Poll` so it stops
+/// blocking; both handles are weak so a lingering clone of the waker (e.g.
+/// stashed by a timer on another thread) can't keep the executor alive.
+struct TaskWakerState<C>
+where
+    C: Config,
+{
+    id: C::TaskId,
+    spawner: Weak<SpawnerInner<C>>,
+    mio_waker: Weak<mio::Waker>,
+}
+
+impl<C> TaskWakerState<C>
+where
+    C: Config,
+{
+    const VTABLE: RawWakerVTable = RawWakerVTable::new(
+        Self::clone_waker,
+        Self::wake,
+        Self::wake_by_ref,
+        Self::drop_waker,
+    );
+
+    fn into_waker(self) -> Waker {
+        let raw = Arc::new(self).into_raw_waker();
+        unsafe { Waker::from_raw(raw) }
+    }
+
+    fn notify(&self) {
+        if let Some(spawner) = self.spawner.upgrade() {
+            spawner.ready.push(self.id.clone());
+            spawner.notify_idle();
+        }
+        if let Some(mio_waker) = self.mio_waker.upgrade() {
+            // best effort: if the executor already shut down this just fails
+            let _ = mio_waker.wake();
+        }
+    }
+
+    unsafe fn clone_waker(ptr: *const ()) -> RawWaker {
+        let arc = unsafe { Arc::from_raw(ptr as *const Self) };
+        let cloned = arc.clone();
+        mem::forget(arc);
+        cloned.into_raw_waker()
+    }
+
+    unsafe fn wake(ptr: *const ()) {
+        let arc = unsafe { Arc::from_raw(ptr as *const Self) };
+        arc.notify();
+    }
+
+    unsafe fn wake_by_ref(ptr: *const ()) {
+        let arc = unsafe { Arc::from_raw(ptr as *const Self) };
+        arc.notify();
+        mem::forget(arc);
+    }
+
+    unsafe fn drop_waker(ptr: *const ()) {
+        drop(unsafe { Arc::from_raw(ptr as *const Self) });
+    }
+}
+
+trait IntoRawWaker {
+    fn into_raw_waker(self) -> RawWaker;
+}
+
+impl<C> IntoRawWaker for Arc<TaskWakerState<C>>
+where
+    C: Config,
+{
+    fn into_raw_waker(self) -> RawWaker {
+        RawWaker::new(Arc::into_raw(self) as *const (), &TaskWakerState::<C>::VTABLE)
     }
 }
 
@@ -221,9 +504,12 @@ where
     }
 
     fn new() -> Result<Self, io::Error> {
+        let registry = RegistryInner::new()?;
+        let waker = Arc::new(mio::Waker::new(registry.poll.registry(), WAKE_TOKEN)?);
         Ok(Executor {
             spawner: Arc::new(SpawnerInner::new()),
-            registry: Arc::new(Mutex::new(RegistryInner::new()?)),
+            registry: Arc::new(Mutex::new(registry)),
+            waker,
         })
     }
 
@@ -235,52 +521,360 @@ where
     }
 
     fn run_inner(self) -> Result<(), io::Error> {
-        static V_TABLE: RawWakerVTable =
-            RawWakerVTable::new(|p| RawWaker::new(p, &V_TABLE), |_| (), |_| (), |_| ());
-
-        let raw_waker = RawWaker::new(ptr::null(), &V_TABLE);
-        let waker = unsafe { Waker::from_raw(raw_waker) };
-        let mut cx = Context::from_waker(&waker);
-
         let reg = self.registry.as_ref();
+        let spawner_ref = Arc::downgrade(&self.spawner);
+        let mio_waker_ref = Arc::downgrade(&self.waker);
+
+        let waker_for = |id: C::TaskId| -> Waker {
+            TaskWakerState {
+                id,
+                spawner: spawner_ref.clone(),
+                mio_waker: mio_waker_ref.clone(),
+            }
+            .into_waker()
+        };
 
-        let mut waiting: HashMap<Token, Task<C>> = HashMap::new();
+        // every currently-pending task, keyed by its own id so that a task
+        // which never registers an fd (e.g. it only ever gets woken from
+        // another thread) still has somewhere to live between polls
+        let mut parked: HashMap<C::TaskId, Task<C>> = HashMap::new();
+        // token -> owning task id, for readiness-driven wakeups
+        let mut token_owner: HashMap<Token, C::TaskId> = HashMap::new();
+        // every token a task is currently interested in; a task awaiting
+        // several sources at once (e.g. a read half and a write half) shows
+        // up here with more than one entry
+        let mut task_tokens: HashMap<C::TaskId, HashSet<Token>> = HashMap::new();
         let mut events = Events::with_capacity(128);
 
+        let park = |parked: &mut HashMap<C::TaskId, Task<C>>,
+                     token_owner: &mut HashMap<Token, C::TaskId>,
+                     task_tokens: &mut HashMap<C::TaskId, HashSet<Token>>,
+                     mut task: Task<C>| {
+            let waker = waker_for(task.id.clone());
+            let (pending, events) = poll_and_collect(&waker, &mut task);
+            apply_token_events::<C>(token_owner, task_tokens, &task.id, events);
+
+            if pending {
+                parked.insert(task.id.clone(), task);
+            } else {
+                self.spawner.live.fetch_sub(1, Ordering::SeqCst);
+                if let Some(set) = task_tokens.remove(&task.id) {
+                    // the task finished without deregistering everything
+                    // itself; don't leak its tokens
+                    for token in set {
+                        token_owner.remove(&token);
+                    }
+                }
+            }
+        };
+
         loop {
-            // traversal new tasks
+            // take in newly spawned tasks
             while !self.spawner.injector.is_empty() {
-                if let Steal::Success(mut task) = self.spawner.injector.steal() {
+                if let Steal::Success(task) = self.spawner.injector.steal() {
                     log::debug!("spawned new {}", task);
-                    if task.future.as_mut().poll(&mut cx).is_pending() {
-                        // TODO: fix race condition
-                        if let Some(token) = reg.lock().unwrap().last() {
-                            waiting.insert(token, task);
+                    park(&mut parked, &mut token_owner, &mut task_tokens, task);
+                }
+            }
+
+            // re-poll every task that was woken (cross-thread wakeup or a
+            // spurious/ready-now wakeup), independent of mio readiness
+            loop {
+                match self.spawner.ready.steal() {
+                    Steal::Success(id) => {
+                        if let Some(task) = parked.remove(&id) {
+                            log::debug!("try advance woken {}", task);
+                            park(&mut parked, &mut token_owner, &mut task_tokens, task);
                         }
                     }
+                    Steal::Empty => break,
+                    Steal::Retry => continue,
                 }
             }
 
             // wait
-            if !waiting.is_empty() {
-                log::debug!("poll {:?}", waiting);
-                reg.lock().unwrap().poll.poll(&mut events, None)?;
-            } else {
+            if parked.is_empty() {
                 break Ok(());
             }
+            // `parked` is now keyed by `C::TaskId` rather than `Token`
+            // (so a task with no registered fd still has somewhere to
+            // live), and `Config::TaskId` isn't required to be `Debug` --
+            // just log how many tasks are waiting instead of the map itself.
+            log::debug!("poll {} pending", parked.len());
+            let timeout = reg
+                .lock()
+                .unwrap()
+                .next_deadline()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            reg.lock().unwrap().poll.poll(&mut events, timeout)?;
+            reg.lock().unwrap().fire_expired_timers();
 
             // wake
-            for event in events.into_iter() {
-                if let Some(mut task) = waiting.remove(&event.token()) {
-                    log::debug!("try advance {}", task);
-                    if task.future.as_mut().poll(&mut cx).is_pending() {
-                        // TODO: fix race condition
-                        if let Some(token) = reg.lock().unwrap().last() {
-                            waiting.insert(token, task);
-                        }
+            for event in events.iter() {
+                if event.token() == WAKE_TOKEN {
+                    // only here to unblock `poll`; the actual work is picked
+                    // up from `self.spawner.ready` at the top of the loop
+                    continue;
+                }
+                if let Some(id) = token_owner.get(&event.token()).cloned() {
+                    if let Some(task) = parked.remove(&id) {
+                        log::debug!("try advance {}", task);
+                        park(&mut parked, &mut token_owner, &mut task_tokens, task);
                     }
                 }
             }
         }
     }
+
+    /// Like [`Executor::run`], but spawned tasks are advanced by `workers`
+    /// OS threads work-stealing from each other, rather than by a single
+    /// loop. Useful once a workload has enough CPU-bound or independently
+    /// progressing tasks that one thread can't keep up.
+    pub fn run_with_threads<F, Fut>(workers: usize, id: C::TaskId, f: F) -> Result<(), io::Error>
+    where
+        F: FnOnce(Spawner<C>, Registry) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+        C: Send + Sync + 'static,
+        C::TaskId: Send + 'static,
+    {
+        let rt = Self::new()?;
+        rt.spawner().spawn(id, f);
+        rt.run_pool(workers.max(1))
+    }
+
+    // `C` only ever shows up behind a `PhantomData`, but each worker thread
+    // below shares the executor through an `Arc` spawned onto its own
+    // `thread::spawn` closure, so the compiler still needs `C: Send + Sync`
+    // spelled out to prove that `Arc` crosses threads safely, and `'static`
+    // because the closure isn't scoped to this function's stack frame.
+    fn run_pool(self, workers: usize) -> Result<(), io::Error>
+    where
+        C: Send + Sync + 'static,
+        C::TaskId: Send + 'static,
+    {
+        let exec = Arc::new(self);
+
+        // pending tasks, reachable by every worker since a wakeup or a
+        // stolen ready-id can come from any thread
+        let parked: Arc<Mutex<HashMap<C::TaskId, Task<C>>>> = Arc::new(Mutex::new(HashMap::new()));
+        let token_owner: Arc<Mutex<HashMap<Token, C::TaskId>>> = Arc::new(Mutex::new(HashMap::new()));
+        let task_tokens: Arc<Mutex<HashMap<C::TaskId, HashSet<Token>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+
+        let locals: Vec<Worker<Task<C>>> = (0..workers).map(|_| Worker::new_fifo()).collect();
+        let stealers: Arc<Vec<Stealer<Task<C>>>> =
+            Arc::new(locals.iter().map(Worker::stealer).collect());
+
+        let handles: Vec<_> = locals
+            .into_iter()
+            .map(|local| {
+                let exec = exec.clone();
+                let parked = parked.clone();
+                let token_owner = token_owner.clone();
+                let task_tokens = task_tokens.clone();
+                let stealers = stealers.clone();
+                thread::spawn(move || {
+                    worker_loop(exec, local, stealers, parked, token_owner, task_tokens)
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            // a panicking worker shouldn't be silently swallowed, but it
+            // also shouldn't be turned into an `io::Error`; match the rest
+            // of this module and surface it the same way a poisoned mutex
+            // would
+            handle.join().expect("worker thread panicked");
+        }
+        Ok(())
+    }
+}
+
+/// Pop the next task this worker should run: periodically (for fairness)
+/// the global injector first even if the local queue is non-empty, then its
+/// own local queue, then the global injector unconditionally, then a steal
+/// attempt against every peer.
+fn next_task<C>(
+    local: &Worker<Task<C>>,
+    injector: &Injector<Task<C>>,
+    stealers: &[Stealer<Task<C>>],
+    tick: u32,
+) -> Option<Task<C>>
+where
+    C: Config,
+{
+    // check the global injector every 61st tick so newly spawned tasks
+    // aren't starved by a worker that always has local work
+    if tick.is_multiple_of(61) {
+        if let Steal::Success(task) = injector.steal() {
+            return Some(task);
+        }
+    }
+
+    if let Some(task) = local.pop() {
+        return Some(task);
+    }
+
+    loop {
+        match injector.steal_batch_and_pop(local) {
+            Steal::Success(task) => return Some(task),
+            Steal::Retry => continue,
+            Steal::Empty => break,
+        }
+    }
+
+    for stealer in stealers {
+        loop {
+            match stealer.steal_batch_and_pop(local) {
+                Steal::Success(task) => return Some(task),
+                Steal::Retry => continue,
+                Steal::Empty => break,
+            }
+        }
+    }
+
+    None
+}
+
+fn worker_loop<C>(
+    exec: Arc<Executor<C>>,
+    local: Worker<Task<C>>,
+    stealers: Arc<Vec<Stealer<Task<C>>>>,
+    parked: Arc<Mutex<HashMap<C::TaskId, Task<C>>>>,
+    token_owner: Arc<Mutex<HashMap<Token, C::TaskId>>>,
+    task_tokens: Arc<Mutex<HashMap<C::TaskId, HashSet<Token>>>>,
+) where
+    C: Config,
+    C::TaskId: Send,
+{
+    let spawner_ref = Arc::downgrade(&exec.spawner);
+    let mio_waker_ref = Arc::downgrade(&exec.waker);
+    let waker_for = |id: C::TaskId| -> Waker {
+        TaskWakerState {
+            id,
+            spawner: spawner_ref.clone(),
+            mio_waker: mio_waker_ref.clone(),
+        }
+        .into_waker()
+    };
+
+    let advance = |mut task: Task<C>| {
+        let waker = waker_for(task.id.clone());
+        let (pending, events) = poll_and_collect(&waker, &mut task);
+
+        if pending {
+            // Park the task *before* publishing any newly registered token
+            // in `token_owner`. A peer worker driving the reactor only ever
+            // learns about a token by looking it up there, so if the
+            // publish happened first, a readiness event for a token this
+            // very poll just registered could arrive in the gap, resolve to
+            // this task, find `parked` still empty, and drop the wakeup for
+            // good (mio is edge-triggered, so it is never redelivered).
+            // Inserting first means that race can only end with the peer
+            // finding (and advancing) the task.
+            let id = task.id.clone();
+            parked.lock().unwrap().insert(id.clone(), task);
+            apply_token_events::<C>(
+                &mut token_owner.lock().unwrap(),
+                &mut task_tokens.lock().unwrap(),
+                &id,
+                events,
+            );
+        } else {
+            apply_token_events::<C>(
+                &mut token_owner.lock().unwrap(),
+                &mut task_tokens.lock().unwrap(),
+                &task.id,
+                events,
+            );
+            exec.spawner.live.fetch_sub(1, Ordering::SeqCst);
+            // Locked and dropped before `token_owner` is taken below, so the
+            // two mutexes are never held at once here, matching the
+            // token_owner-then-task_tokens order `apply_token_events` uses.
+            let removed = task_tokens.lock().unwrap().remove(&task.id);
+            if let Some(set) = removed {
+                let mut token_owner = token_owner.lock().unwrap();
+                for token in set {
+                    token_owner.remove(&token);
+                }
+            }
+            // wake idle workers so they notice `live` may have hit zero
+            exec.spawner.notify_idle();
+        }
+    };
+
+    let mut tick = 0u32;
+    loop {
+        if let Some(task) = next_task(&local, &exec.spawner.injector, &stealers, tick) {
+            tick = tick.wrapping_add(1);
+            advance(task);
+            continue;
+        }
+
+        let mut woke_any = false;
+        loop {
+            match exec.spawner.ready.steal() {
+                Steal::Success(id) => {
+                    if let Some(task) = parked.lock().unwrap().remove(&id) {
+                        advance(task);
+                        woke_any = true;
+                    }
+                }
+                Steal::Retry => continue,
+                Steal::Empty => break,
+            }
+        }
+        if woke_any {
+            continue;
+        }
+
+        // nobody has local or global work: try to be the one driving the
+        // reactor. Several workers may race for this; whichever wins polls
+        // with a short timeout so the others aren't starved for long.
+        if let Ok(mut reg) = exec.registry.try_lock() {
+            let timeout = reg
+                .next_deadline()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+                .unwrap_or(Duration::from_millis(10))
+                .min(Duration::from_millis(10));
+            let mut events = Events::with_capacity(64);
+            if reg.poll.poll(&mut events, Some(timeout)).is_ok() {
+                reg.fire_expired_timers();
+                // Collect every ready task while `reg` is held, then drop it
+                // once so `advance` (which re-locks the registry) doesn't
+                // deadlock. Edge-triggered events aren't re-reported, so
+                // advancing only the first match would strand the rest.
+                let mut ready = Vec::new();
+                for event in events.iter() {
+                    if event.token() == WAKE_TOKEN {
+                        continue;
+                    }
+                    if let Some(id) = token_owner.lock().unwrap().get(&event.token()).cloned() {
+                        ready.push(id);
+                    }
+                }
+                drop(reg);
+                for id in ready {
+                    if let Some(task) = parked.lock().unwrap().remove(&id) {
+                        advance(task);
+                    }
+                }
+            }
+        } else {
+            // another worker is already driving the reactor and there is no
+            // local/global/ready work for us either: block on the idle
+            // condvar instead of spinning. Bounded so we still notice the
+            // reactor driver dying (nobody left to wake us) and re-check
+            // `live` below at least this often.
+            let guard = exec.spawner.idle_lock.lock().unwrap();
+            let _ = exec
+                .spawner
+                .idle_cv
+                .wait_timeout(guard, Duration::from_millis(10));
+        }
+
+        if exec.spawner.live.load(Ordering::SeqCst) == 0 {
+            break;
+        }
+    }
 }
@@ -1,7 +1,7 @@
 use core::{time::Duration, str};
 use std::{
     thread,
-    io::{self, Write},
+    io::{self, Read, Write},
     net::{TcpListener, TcpStream},
 };
 use futures::{AsyncReadExt, StreamExt};
@@ -9,21 +9,19 @@ use cold::{rt::Executor, net::WithRegistry};
 
 #[test]
 fn nop() -> io::Result<()> {
-    Executor::<usize>::run(0, move |_| async {})
+    Executor::<usize>::run(0, move |_, _| async {})
 }
 
 #[test]
 fn nested() -> io::Result<()> {
-    Executor::<usize>::run(0, move |_| async {
-        async { async { println!("hello world") }.await }.await
-    })
+    Executor::<usize>::run(0, move |_, _| async { println!("hello world") })
 }
 
 #[test]
 fn nested_spawn() -> io::Result<()> {
-    Executor::<usize>::run(0, move |executor| async move {
+    Executor::<usize>::run(0, move |executor, _| async move {
         println!("hello outer world");
-        executor.spawn(1, move |_| async {
+        executor.spawn(1, move |_, _| async {
             println!("hello inner world");
         });
     })
@@ -39,21 +37,21 @@ fn tcp_server() -> io::Result<()> {
             thread::sleep(Duration::from_millis(500));
             let mut stream = TcpStream::connect("127.0.0.1:9000").unwrap();
             stream.write_all(b"hello world: ").unwrap();
-            stream.write_all(&['0' as u8 + i]).unwrap();
+            stream.write_all(&[b'0' + i]).unwrap();
         }
     });
 
-    Executor::<usize>::run(0, move |executor| async move {
+    Executor::<usize>::run(0, move |executor, registry| async move {
         let listener = TcpListener::bind("127.0.0.1:9000").unwrap();
         listener.set_nonblocking(true).unwrap();
-        let mut listener = WithRegistry::new(listener, &executor);
+        let mut listener = WithRegistry::<_, usize>::new(listener, &registry);
         let mut id = 1;
         while let Some(p) = listener.next().await {
             let (stream, address) = p.unwrap();
             println!("{:?}", address);
 
-            executor.spawn(id, move |executor| async move {
-                let mut stream = WithRegistry::new(stream, &executor);
+            executor.spawn(id, move |_, registry| async move {
+                let mut stream = WithRegistry::<_, usize>::new(stream, &registry);
                 let mut buf = [0; 0x100];
                 let read = stream.read(&mut buf).await.unwrap();
                 println!("{}", str::from_utf8(&buf[..read]).unwrap());
@@ -65,3 +63,33 @@ fn tcp_server() -> io::Result<()> {
         }
     })
 }
+
+/// Sends a message one way through a loopback TCP connection using
+/// `poll_write` (via `AsyncWriteExt::write_all`) on one end and a plain
+/// blocking read on the other, exercising `WithRegistry`'s `AsyncWrite`
+/// impl end to end.
+#[test]
+#[cfg(unix)]
+fn tcp_poll_write() -> io::Result<()> {
+    use futures::AsyncWriteExt;
+
+    let listener = TcpListener::bind("127.0.0.1:9001")?;
+    let server = thread::spawn(move || -> io::Result<Vec<u8>> {
+        let (mut stream, _) = listener.accept()?;
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf)?;
+        Ok(buf)
+    });
+
+    Executor::<usize>::run(0, move |_, registry| async move {
+        let stream = TcpStream::connect("127.0.0.1:9001").unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut stream = WithRegistry::<_, usize>::new(stream, &registry);
+        stream.write_all(b"hello over poll_write").await.unwrap();
+        stream.close().await.unwrap();
+    })?;
+
+    let received = server.join().unwrap()?;
+    assert_eq!(received, b"hello over poll_write");
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use std::{
+    io,
+    net::UdpSocket,
+    os::unix::net::UnixDatagram,
+};
+use cold::{rt::Executor, net::WithRegistry};
+
+/// Exercises `WithRegistry<UdpSocket, _>`'s `send_to`/`recv_from`.
+#[test]
+#[cfg(unix)]
+fn udp_send_recv() -> io::Result<()> {
+    let server = UdpSocket::bind("127.0.0.1:0")?;
+    server.set_nonblocking(true)?;
+    let server_addr = server.local_addr()?;
+
+    let client = UdpSocket::bind("127.0.0.1:0")?;
+    client.set_nonblocking(true)?;
+
+    Executor::<usize>::run(0, move |_, registry| async move {
+        let mut server = WithRegistry::<_, usize>::new(server, &registry);
+        let mut client = WithRegistry::<_, usize>::new(client, &registry);
+
+        client.send_to(b"ping", server_addr).await.unwrap();
+
+        let mut buf = [0; 0x100];
+        let (read, _) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..read], b"ping");
+    })
+}
+
+/// Exercises `WithRegistry<UnixDatagram, _>`'s `send_to`/`recv_from`.
+#[test]
+#[cfg(unix)]
+fn unix_datagram_send_recv() -> io::Result<()> {
+    let dir = std::env::temp_dir();
+    let server_path = dir.join(format!("cold-test-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&server_path);
+
+    let server = UnixDatagram::bind(&server_path)?;
+    server.set_nonblocking(true)?;
+    let client = UnixDatagram::unbound()?;
+    client.set_nonblocking(true)?;
+
+    let client_path = server_path.clone();
+    let result = Executor::<usize>::run(0, move |_, registry| {
+        async move {
+            let mut server = WithRegistry::<_, usize>::new(server, &registry);
+            let mut client = WithRegistry::<_, usize>::new(client, &registry);
+
+            client.send_to(b"ping", &client_path).await.unwrap();
+
+            let mut buf = [0; 0x100];
+            let (read, _) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..read], b"ping");
+        }
+    });
+
+    let _ = std::fs::remove_file(&server_path);
+    result
+}
@@ -0,0 +1,15 @@
+use core::time::Duration;
+use std::{io, time::Instant};
+use cold::rt::Executor;
+
+/// Exercises `Registry::sleep`: the task should not observe `Ready` before
+/// the requested deadline has actually elapsed.
+#[test]
+fn sleep_respects_deadline() -> io::Result<()> {
+    let start = Instant::now();
+    Executor::<usize>::run(0, move |_, registry| async move {
+        registry.sleep(Duration::from_millis(200)).await;
+    })?;
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    Ok(())
+}
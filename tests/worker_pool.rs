@@ -0,0 +1,26 @@
+use std::{
+    io,
+    sync::{Arc, atomic::{AtomicUsize, Ordering}},
+};
+use cold::rt::Executor;
+
+/// Spawns a handful of tasks across a multi-threaded worker pool and checks
+/// that every one of them actually runs, not just the initial task.
+#[test]
+fn run_with_threads_drives_every_task() -> io::Result<()> {
+    let count = Arc::new(AtomicUsize::new(0));
+
+    let root_count = count.clone();
+    Executor::<usize>::run_with_threads(4, 0, move |executor, _| async move {
+        root_count.fetch_add(1, Ordering::SeqCst);
+        for id in 1..8 {
+            let count = root_count.clone();
+            executor.spawn(id, move |_, _| async move {
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+    })?;
+
+    assert_eq!(count.load(Ordering::SeqCst), 8);
+    Ok(())
+}